@@ -8,6 +8,7 @@
 use cpu::Mem;
 use rom::Rom;
 use util::debug_assert;
+use std::io::{Reader, Writer};
 
 //
 // Registers
@@ -18,8 +19,10 @@ struct Regs {
     mask: PpuMask,      // PPUMASK: 0x2001
     status: PpuStatus,  // PPUSTATUS: 0x2002
     oam_addr: u8,       // OAMADDR: 0x2003
-    scroll: PpuScroll,  // PPUSCROLL: 0x2005
-    addr: u16,          // PPUADDR: 0x2006
+    v: u16,             // current VRAM address (15 bits)
+    t: u16,             // temporary VRAM address (15 bits)
+    x: u8,              // fine X scroll (3 bits)
+    w: bool,            // first/second write toggle for PPUSCROLL/PPUADDR
 }
 
 //
@@ -35,7 +38,7 @@ enum SpriteSize {
 
 impl PpuCtrl {
     fn base_nametable_addr(self) -> u16           { 0x2000 + (*self & 0x3) as u16 * 0x400 }
-    fn vram_addr_increment(self) -> u16           { if (*self & 0x04) == 0 { 0 } else { 32 } }
+    fn vram_addr_increment(self) -> u16           { if (*self & 0x04) == 0 { 1 } else { 32 } }
     fn sprite_pattern_table_addr(self) -> u16     { if (*self & 0x08) == 0 { 0 } else { 0x1000 } }
     fn background_pattern_table_addr(self) -> u16 { if (*self & 0x10) == 0 { 0 } else { 0x1000 } }
     fn sprite_size(self) -> SpriteSize {
@@ -59,6 +62,7 @@ impl PpuMask {
     fn intensify_reds(self) -> bool          { (*self & 0x20) != 0 }
     fn intensify_greens(self) -> bool        { (*self & 0x40) != 0 }
     fn intensity_blues(self) -> bool         { (*self & 0x80) != 0 }
+    fn rendering_enabled(self) -> bool       { self.show_background() || self.show_sprites() }
 }
 
 //
@@ -78,55 +82,196 @@ impl PpuStatus {
     fn set_in_vblank(&mut self, val: bool) {
         if val { *self = PpuStatus(**self | 0x80) } else { *self = PpuStatus(**self & !0x80) }
     }
+    fn in_vblank(self) -> bool { (*self & 0x80) != 0 }
 }
 
 //
-// PPUSCROLL: 0x2005
+// Nametable mirroring, as configured by the cartridge (iNES header bit 0,
+// or overridden by a mapper that wires the CIRAM lines itself).
 //
 
-struct PpuScroll {
-    x: u8,
-    y: u8,
-    next: PpuScrollDir
+pub enum MirrorType {
+    Horizontal,
+    Vertical,
+    SingleScreen0,
+    SingleScreen1,
+    FourScreen,
 }
 
-enum PpuScrollDir {
-    XDir,
-    YDir,
+impl MirrorType {
+    // Maps one of the four logical 0x400-byte nametable slots (selected by
+    // address bits 11-10) onto a physical bank index into `PpuMem`'s
+    // backing nametable storage.
+    fn physical_bank(self, logical_slot: u16) -> u16 {
+        match self {
+            Horizontal => logical_slot >> 1,       // $2000=$2400, $2800=$2C00
+            Vertical => logical_slot & 0x1,        // $2000=$2800, $2400=$2C00
+            SingleScreen0 => 0,
+            SingleScreen1 => 1,
+            FourScreen => logical_slot,            // all four banks distinct
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Horizontal => 0,
+            Vertical => 1,
+            SingleScreen0 => 2,
+            SingleScreen1 => 3,
+            FourScreen => 4,
+        }
+    }
+
+    fn from_u8(val: u8) -> MirrorType {
+        match val {
+            0 => Horizontal,
+            1 => Vertical,
+            2 => SingleScreen0,
+            3 => SingleScreen1,
+            4 => FourScreen,
+            _ => fail ~"invalid MirrorType in save state"
+        }
+    }
+}
+
+//
+// Mappers own the cartridge's bank-switched CHR (and, eventually, PRG)
+// address space. `PpuMem` no longer assumes a fixed 8 KiB CHR-ROM; it just
+// delegates pattern-table accesses to whatever mapper the cartridge needs,
+// which is exactly the same seam the CPU memory map will want for PRG
+// bank switching.
+//
+
+pub trait Mapper {
+    fn chr_loadb(&self, addr: u16) -> u8;
+    fn chr_storeb(&mut self, addr: u16, val: u8);
+}
+
+// Mapper 3 (CNROM): fixed 16/32 KiB PRG, up to four swappable 8 KiB CHR
+// banks selected by any write to $8000-$FFFF.
+
+pub struct CnromMapper {
+    chr: ~[u8],       // all CHR banks concatenated, 8 KiB apiece
+    chr_ram: bool,    // CHR-RAM carts accept writes below $2000
+    bank: u8,
+    bank_mask: u8,    // (bank count - 1); masks the selected bank on write
+}
+
+impl CnromMapper {
+    pub fn new(rom: &Rom) -> CnromMapper {
+        CnromMapper::from_chr(rom.chr.clone())
+    }
+
+    // Split out of `new` so the CHR-RAM allocation path (rom_chr empty) can
+    // be exercised directly in tests without needing a full `Rom`.
+    fn from_chr(rom_chr: ~[u8]) -> CnromMapper {
+        let chr_ram = rom_chr.is_empty();
+        // CHR-RAM carts have no CHR data in the ROM image; allocate a single
+        // zeroed 8 KiB bank for the game to write tiles into at runtime.
+        let chr = if chr_ram { ~[0u8 * 0x2000] } else { rom_chr };
+        let bank_count = (chr.len() / 0x2000) as u8;
+        CnromMapper {
+            chr: chr,
+            chr_ram: chr_ram,
+            bank: 0,
+            bank_mask: if bank_count == 0 { 0 } else { bank_count - 1 },
+        }
+    }
+
+    // Called by the CPU memory map on writes to $8000-$FFFF.
+    pub fn select_chr_bank(&mut self, val: u8) {
+        self.bank = val & self.bank_mask;
+    }
+}
+
+impl CnromMapper : Mapper {
+    fn chr_loadb(&self, addr: u16) -> u8 {
+        self.chr[self.bank as u16 * 0x2000 + addr]
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.chr[self.bank as u16 * 0x2000 + addr] = val;
+        }
+        // Else CHR-ROM: writes below $2000 are ignored.
+    }
 }
 
 // PPU memory. This implements the same Mem trait that the CPU memory does.
 
-pub struct PpuMem {
-    rom: &Rom,
-    nametables: [u8 * 0x1000],  // 4 nametables, 0x400 each
+pub struct PpuMem<M> {
+    mapper: M,
+    // Mirroring mode for this cartridge, read out of the iNES header (or a
+    // mapper's override, e.g. four-screen via extra CIRAM). `FourScreen`
+    // is the only mode that actually touches all four banks below.
+    mirroring: MirrorType,
+    nametables: [u8 * 0x1000],  // 4 physical 0x400 banks, addressed via mirroring
     palette: [u8 * 0x20],
 }
 
-impl PpuMem : Mem {
+impl<M:Mapper> PpuMem<M> {
+    // Translates a $2000-$3EFF nametable address through the cartridge's
+    // mirroring mode to get an index into `nametables`.
+    fn translate_nametable_addr(&self, addr: u16) -> u16 {
+        let offset = addr & 0x03ff;
+        let logical_slot = (addr >> 10) & 0x3;
+        let bank = self.mirroring.physical_bank(logical_slot);
+        bank * 0x400 + offset
+    }
+
+    // Translates a $3F00-$3FFF palette address, folding the four
+    // sprite-palette color-0 mirrors ($3F10/$3F14/$3F18/$3F1C) down onto
+    // their background-palette counterparts ($3F00/$3F04/$3F08/$3F0C).
+    fn translate_palette_addr(addr: u16) -> u16 {
+        let addr = addr & 0x1f;
+        if (addr & 0x10) != 0 && (addr & 0x3) == 0 { addr & !0x10 } else { addr }
+    }
+
+    // Snapshots the nametable/palette RAM and the mirroring mode. Mapper
+    // state (CHR bank selection, CHR-RAM contents) is cartridge state, not
+    // PPU state -- like the ROM reference, it's excluded here and expected
+    // to be saved alongside the cartridge by the front-end.
+    fn save_state(&self, out: &mut Writer) {
+        out.write_u8(PPU_MEM_STATE_VERSION);
+        out.write_u8(self.mirroring.to_u8());
+        out.write(self.nametables);
+        out.write(self.palette);
+    }
+
+    fn load_state(&mut self, src: &mut Reader) {
+        let version = src.read_u8();
+        debug_assert(version == PPU_MEM_STATE_VERSION, "unsupported PpuMem save-state version");
+        self.mirroring = MirrorType::from_u8(src.read_u8());
+        src.read(self.nametables);
+        src.read(self.palette);
+    }
+}
+
+static PPU_MEM_STATE_VERSION: u8 = 1;
+
+impl<M:Mapper> PpuMem<M> : Mem {
     fn loadb(&mut self, addr: u16) -> u8 {
         if addr < 0x2000 {          // Tilesets 0 or 1
-            return self.rom.chr[addr]
+            return self.mapper.chr_loadb(addr)
         }
         if addr < 0x3f00 {          // Name table area
-            let addr = addr & 0x0fff;
+            let addr = self.translate_nametable_addr(addr);
             return self.nametables[addr]
         }
         if addr < 0x4000 {          // Palette area
-            let addr = addr & 0x1f;
+            let addr = PpuMem::translate_palette_addr(addr);
             return self.palette[addr]
         }
         fail ~"invalid VRAM read"
     }
     fn storeb(&mut self, addr: u16, val: u8) {
-        if addr < 0x2000 {
-            return                  // Attempt to write to CHR-ROM; ignore.
+        if addr < 0x2000 {          // Tilesets 0 or 1
+            return self.mapper.chr_storeb(addr, val)
         }
         if addr < 0x3f00 {          // Name table area
-            let addr = addr & 0x0fff;
+            let addr = self.translate_nametable_addr(addr);
             self.nametables[addr] = val;
         } else if addr < 0x4000 {   // Palette area
-            let addr = addr & 0x1f;
+            let addr = PpuMem::translate_palette_addr(addr);
             self.palette[addr] = val;
         }
     }
@@ -140,24 +285,225 @@ impl PpuMem : Mem {
     }
 }
 
+//
+// The video sink. A front-end implements this to receive composited pixels
+// as the renderer produces them, one at a time, in raster order.
+//
+
+pub trait Screen {
+    fn put(&mut self, x: u8, y: u8, color: u8);
+}
+
+//
+// Background rendering pipeline state. This is the bundle of shift
+// registers, latches, and fetch scratch space that the 8-cycle tile fetch
+// cadence reads and writes as the renderer crosses a scanline.
+//
+
+struct BgPipeline {
+    // 16-bit shift registers holding pattern-table bits for the current and
+    // next tile. The high bit (after shifting) is the one about to be
+    // output; fine_x selects how far into the register to look.
+    pattern_lo: u16,
+    pattern_hi: u16,
+
+    // 8-bit shift registers holding the attribute-table palette-select bit
+    // for the current and next tile, expanded out one bit per pixel.
+    attr_lo: u8,
+    attr_hi: u8,
+
+    // Latches reloaded from the attribute byte at the end of each tile;
+    // shifted into attr_lo/attr_hi one bit at a time as the tile scrolls by.
+    attr_latch_lo: bool,
+    attr_latch_hi: bool,
+
+    // Fetch scratch: the byte most recently read during the 8-cycle cadence,
+    // waiting to be latched into the shift registers at the tile boundary.
+    nt_byte: u8,
+    at_byte: u8,
+    pt_lo_byte: u8,
+    pt_hi_byte: u8,
+}
+
+impl BgPipeline {
+    fn new() -> BgPipeline {
+        BgPipeline {
+            pattern_lo: 0, pattern_hi: 0,
+            attr_lo: 0, attr_hi: 0,
+            attr_latch_lo: false, attr_latch_hi: false,
+            nt_byte: 0, at_byte: 0, pt_lo_byte: 0, pt_hi_byte: 0,
+        }
+    }
+
+    fn save_state(&self, out: &mut Writer) {
+        out.write_le_u16(self.pattern_lo);
+        out.write_le_u16(self.pattern_hi);
+        out.write_u8(self.attr_lo);
+        out.write_u8(self.attr_hi);
+        out.write_u8(self.attr_latch_lo as u8);
+        out.write_u8(self.attr_latch_hi as u8);
+        out.write_u8(self.nt_byte);
+        out.write_u8(self.at_byte);
+        out.write_u8(self.pt_lo_byte);
+        out.write_u8(self.pt_hi_byte);
+    }
+
+    fn load_state(&mut self, src: &mut Reader) {
+        self.pattern_lo = src.read_le_u16();
+        self.pattern_hi = src.read_le_u16();
+        self.attr_lo = src.read_u8();
+        self.attr_hi = src.read_u8();
+        self.attr_latch_lo = src.read_u8() != 0;
+        self.attr_latch_hi = src.read_u8() != 0;
+        self.nt_byte = src.read_u8();
+        self.at_byte = src.read_u8();
+        self.pt_lo_byte = src.read_u8();
+        self.pt_hi_byte = src.read_u8();
+    }
+}
+
+//
+// Sprite rendering pipeline state.
+//
+// Evaluation happens once per scanline (at the real hardware's cycle 257)
+// rather than dot-by-dot: we scan all 64 primary OAM entries for ones that
+// cover the *next* scanline, copy up to eight of them into `secondary_oam`,
+// and immediately fetch their pattern bytes into `slots`. This collapses
+// hardware's two-phase clear/evaluate/fetch cycle into one step, which is
+// observationally equivalent for any game that isn't probing OAM decay or
+// the overflow-detection hardware bug.
+//
+
+struct SpriteSlot {
+    pattern_lo: u8,     // already flipped horizontally if attr bit 6 is set
+    pattern_hi: u8,
+    attr: u8,           // raw OAM attribute byte (palette, priority, flip)
+    x: u8,              // screen X of the sprite's left edge
+    is_sprite_zero: bool,
+}
+
+impl SpriteSlot {
+    fn empty() -> SpriteSlot {
+        SpriteSlot { pattern_lo: 0, pattern_hi: 0, attr: 0, x: 0xff, is_sprite_zero: false }
+    }
+
+    fn save_state(&self, out: &mut Writer) {
+        out.write_u8(self.pattern_lo);
+        out.write_u8(self.pattern_hi);
+        out.write_u8(self.attr);
+        out.write_u8(self.x);
+        out.write_u8(self.is_sprite_zero as u8);
+    }
+
+    fn load_state(&mut self, src: &mut Reader) {
+        self.pattern_lo = src.read_u8();
+        self.pattern_hi = src.read_u8();
+        self.attr = src.read_u8();
+        self.x = src.read_u8();
+        self.is_sprite_zero = src.read_u8() != 0;
+    }
+}
+
+struct SpritePipeline {
+    secondary_oam: [u8 * 32],  // up to 8 sprites x 4 bytes, copied from primary OAM
+    slots: [SpriteSlot * 8],
+    count: u8,                 // number of slots populated for the current scanline
+}
+
+impl SpritePipeline {
+    fn new() -> SpritePipeline {
+        SpritePipeline {
+            secondary_oam: [0u8 * 0x20],
+            slots: [SpriteSlot::empty() * 8],
+            count: 0,
+        }
+    }
+
+    fn save_state(&self, out: &mut Writer) {
+        out.write(self.secondary_oam);
+        let mut i = 0;
+        while i < 8 {
+            self.slots[i].save_state(out);
+            i += 1;
+        }
+        out.write_u8(self.count);
+    }
+
+    fn load_state(&mut self, src: &mut Reader) {
+        src.read(self.secondary_oam);
+        let mut i = 0;
+        while i < 8 {
+            self.slots[i].load_state(src);
+            i += 1;
+        }
+        self.count = src.read_u8();
+    }
+}
+
+fn reverse_bits(mut val: u8) -> u8 {
+    let mut out = 0u8;
+    let mut i = 0;
+    while i < 8 {
+        out = (out << 1) | (val & 1);
+        val >>= 1;
+        i += 1;
+    }
+    out
+}
+
 // The main PPU structure. This structure is separate from the PPU memory just as the CPU is.
 
-struct Ppu<VM,OM> {
+pub struct Ppu<VM,OM,S> {
     regs: Regs,
     vram: VM,
     oam: OM,
+    screen: S,
+    bg: BgPipeline,
+    sprites: SpritePipeline,
+
+    // Raster position. `scanline` runs 0..261 inclusive (0-239 visible, 240
+    // post-render, 241 start of vblank, 261 pre-render); `cycle` runs
+    // 0..340 inclusive within a scanline.
+    scanline: u16,
+    cycle: u16,
+
+    // Toggles every frame. On odd frames, with rendering enabled, the
+    // pre-render scanline is one dot short (the well-known NES skipped-dot
+    // quirk), which keeps the PPU/APU clock ratio exact over time.
+    // Read and toggled by `advance_dot`.
+    odd_frame: bool,
+
+    // NMI edge detection. `nmi_line` mirrors the PPU's physical /NMI output
+    // (in_vblank && vblank_nmi enabled); `nmi_pending` counts rising edges
+    // on that line that the CPU hasn't yet taken via `take_nmi`. Because
+    // the output is a logical AND of the vblank flag and the PPUCTRL enable
+    // bit, toggling the enable bit while vblank is still set produces a
+    // fresh rising edge -- and hence another queued NMI -- without the
+    // vblank flag itself changing.
+    nmi_line: bool,
+    nmi_pending: u8,
+
+    // The PPUDATA read buffer: reads below the palette region return this
+    // stale value and only then trigger the fetch that will satisfy the
+    // *next* read, mirroring the real PPU's internal read-ahead latch.
+    ppudata_buffer: u8,
 }
 
-impl<VM:Mem,OM:Mem> Ppu<VM,OM> {
+static PRE_RENDER_SCANLINE: u16 = 261;
+static VISIBLE_SCANLINES: u16 = 240;
+static CYCLES_PER_SCANLINE: u16 = 341;
+static SCANLINES_PER_FRAME: u16 = 262;
+
+impl<VM:Mem,OM:Mem,S:Screen> Ppu<VM,OM,S> {
     // Performs a store to the PPU register at the given CPU address.
     fn storeb(&mut self, addr: u16, val: u8) {
         debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
         match addr & 7 {
-            0 => self.regs.ctrl = PpuCtrl(val),
+            0 => self.update_ppuctrl(val),
             1 => self.regs.mask = PpuMask(val),
             2 => (),    // PPUSTATUS is read-only
             3 => self.regs.oam_addr = val,
-            4 => fail ~"OAM write unimplemented",
+            4 => self.write_oamdata(val),
             5 => self.update_ppuscroll(val),
             6 => self.update_ppuaddr(val),
             7 => self.write_ppudata(val),
@@ -165,26 +511,690 @@ impl<VM:Mem,OM:Mem> Ppu<VM,OM> {
         }
     }
 
+    fn update_ppuctrl(&mut self, val: u8) {
+        self.regs.ctrl = PpuCtrl(val);
+        // Bits 0-1 of PPUCTRL select the base nametable and live in t[10,11].
+        self.regs.t = (self.regs.t & !0x0c00) | ((val & 0x3) as u16 << 10);
+        // Toggling the NMI-enable bit while vblank is still set re-triggers
+        // the /NMI line even though the vblank flag itself didn't move.
+        self.update_nmi_line();
+    }
+
     fn update_ppuscroll(&mut self, val: u8) {
-        match self.regs.scroll.next {
-            XDir => {
-                self.regs.scroll.x = val;
-                self.regs.scroll.next = YDir;
-            }
-            YDir => {
-                self.regs.scroll.y = val;
-                self.regs.scroll.next = XDir;
-            }
+        if !self.regs.w {
+            // First write: coarse X (t[4,0]) and fine X (x).
+            self.regs.t = (self.regs.t & !0x001f) | (val >> 3) as u16;
+            self.regs.x = val & 0x07;
+            self.regs.w = true;
+        } else {
+            // Second write: coarse Y (t[9,5]) and fine Y (t[14,12]).
+            self.regs.t = (self.regs.t & !0x73e0)
+                | ((val & 0x07) as u16 << 12)
+                | ((val & 0xf8) as u16 << 2);
+            self.regs.w = false;
         }
     }
 
     fn update_ppuaddr(&mut self, val: u8) {
-        self.regs.addr = (self.regs.addr << 8) | (val as u16);
+        if !self.regs.w {
+            // First write: high 6 bits of t; bit 14 is always cleared.
+            self.regs.t = (self.regs.t & 0x00ff) | ((val & 0x3f) as u16 << 8);
+            self.regs.w = true;
+        } else {
+            // Second write: low byte of t, then t is copied to v.
+            self.regs.t = (self.regs.t & 0xff00) | (val as u16);
+            self.regs.v = self.regs.t;
+            self.regs.w = false;
+        }
     }
 
     fn write_ppudata(&mut self, val: u8) {
-        self.vram.storeb(self.regs.addr, val);
-        self.regs.addr += self.regs.ctrl.vram_addr_increment();
+        self.vram.storeb(self.regs.v & 0x3fff, val);
+        self.regs.v += self.regs.ctrl.vram_addr_increment();
+    }
+
+    // Performs a load from the PPU register at the given CPU address.
+    pub fn loadb(&mut self, addr: u16) -> u8 {
+        debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
+        match addr & 7 {
+            2 => self.read_ppustatus(),
+            4 => self.oam.loadb(self.regs.oam_addr as u16),
+            7 => self.read_ppudata(),
+            // PPUCTRL/PPUMASK/OAMADDR/PPUSCROLL/PPUADDR are write-only;
+            // real hardware returns open-bus garbage here (see the TODO on
+            // PpuStatus), which we don't model.
+            _ => 0,
+        }
+    }
+
+    fn read_ppustatus(&mut self) -> u8 {
+        let status = *self.regs.status;
+        self.regs.w = false;
+        self.regs.status.set_in_vblank(false);
+        self.update_nmi_line();
+        status
+    }
+
+    fn read_ppudata(&mut self) -> u8 {
+        let addr = self.regs.v & 0x3fff;
+        let result = if addr < 0x3f00 {
+            let buffered = self.ppudata_buffer;
+            self.ppudata_buffer = self.vram.loadb(addr);
+            buffered
+        } else {
+            // Palette reads return immediately, but the buffer is still
+            // refilled from the nametable mirrored underneath the palette.
+            let value = self.vram.loadb(addr);
+            self.ppudata_buffer = self.vram.loadb(addr - 0x1000);
+            value
+        };
+        self.regs.v += self.regs.ctrl.vram_addr_increment();
+        result
+    }
+
+    fn write_oamdata(&mut self, val: u8) {
+        self.oam.storeb(self.regs.oam_addr as u16, val);
+        self.regs.oam_addr += 1;
+    }
+
+    // Entry point for the CPU bus's $4014 OAMDMA handler: copies a full
+    // 256-byte CPU page into OAM, starting at (and wrapping from) the
+    // current OAMADDR, exactly as the real DMA unit does.
+    pub fn oam_dma(&mut self, page: &[u8]) {
+        debug_assert(page.len() == 256, "OAM DMA expects a 256-byte CPU page");
+        let mut i: u16 = 0;
+        while i < 256 {
+            let dest = (self.regs.oam_addr as u16 + i) & 0xff;
+            self.oam.storeb(dest, page[i]);
+            i += 1;
+        }
+    }
+
+    // Advances the PPU by a single dot (341 per scanline, 262 scanlines per
+    // frame), performing the background fetch/shift/composite work and
+    // emitting a pixel through `screen` for every visible dot.
+    fn step(&mut self) {
+        let rendering = self.regs.mask.rendering_enabled();
+        let visible_line = self.scanline < VISIBLE_SCANLINES;
+        let prerender_line = self.scanline == PRE_RENDER_SCANLINE;
+
+        // Vblank set/clear happens on every frame regardless of whether
+        // rendering is enabled.
+        if self.scanline == VISIBLE_SCANLINES + 1 && self.cycle == 1 {
+            self.enter_vblank();
+        }
+        if prerender_line && self.cycle == 1 {
+            self.leave_vblank();
+        }
+
+        if rendering && (visible_line || prerender_line) {
+            self.run_background_pipeline(visible_line);
+        }
+
+        if visible_line && self.cycle >= 1 && self.cycle <= 256 {
+            self.output_pixel();
+        }
+
+        self.advance_dot(rendering);
+    }
+
+    fn enter_vblank(&mut self) {
+        self.regs.status.set_in_vblank(true);
+        self.update_nmi_line();
+    }
+
+    fn leave_vblank(&mut self) {
+        self.regs.status.set_in_vblank(false);
+        self.regs.status.set_sprite_zero_hit(false);
+        self.regs.status.set_sprite_overflow(false);
+        self.update_nmi_line();
+    }
+
+    fn update_nmi_line(&mut self) {
+        let line = self.regs.status.in_vblank() && self.regs.ctrl.vblank_nmi();
+        if line && !self.nmi_line {
+            self.nmi_pending += 1;
+        }
+        self.nmi_line = line;
+    }
+
+    // Polled by the CPU/memory layer once per instruction boundary. Because
+    // the PPU only ever queues a pending NMI mid-instruction (from `step`
+    // or a PPUCTRL write) and this is only drained between instructions,
+    // the request is naturally serviced after the current instruction
+    // completes -- the one-instruction delay the vbl-NMI timing test
+    // requires.
+    pub fn take_nmi(&mut self) -> bool {
+        if self.nmi_pending > 0 {
+            self.nmi_pending -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn run_background_pipeline(&mut self, visible_line: bool) {
+        let cycle = self.cycle;
+
+        let fetching = (cycle >= 1 && cycle <= 256) || (cycle >= 321 && cycle <= 336);
+        if fetching {
+            if cycle >= 1 {
+                self.shift_background_registers();
+            }
+            match cycle % 8 {
+                1 => self.fetch_nametable_byte(),
+                3 => self.fetch_attribute_byte(),
+                5 => self.fetch_pattern_low_byte(),
+                7 => self.fetch_pattern_high_byte(),
+                0 => {
+                    self.reload_background_shift_registers();
+                    self.increment_coarse_x();
+                }
+                _ => ()
+            }
+        }
+
+        if cycle == 256 {
+            self.increment_y();
+        }
+        if cycle == 257 {
+            self.copy_horizontal_bits();
+        }
+        if !visible_line && cycle >= 280 && cycle <= 304 {
+            self.copy_vertical_bits();
+        }
+
+        // Real hardware spends cycles 65-256 evaluating and 257-320
+        // fetching; we do both in one shot at 257, which lands after this
+        // line's own sprite slots (built last line) have been fully read by
+        // output_pixel() and before the CPU could observe OAMADDR changing.
+        if cycle == 257 {
+            let next_line = if visible_line { self.scanline + 1 } else { 0 };
+            self.evaluate_and_fetch_sprites(next_line);
+        }
+    }
+
+    fn fetch_nametable_byte(&mut self) {
+        let addr = 0x2000 | (self.regs.v & 0x0fff);
+        self.bg.nt_byte = self.vram.loadb(addr);
+    }
+
+    fn fetch_attribute_byte(&mut self) {
+        let v = self.regs.v;
+        let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        self.bg.at_byte = self.vram.loadb(addr);
+    }
+
+    fn fetch_pattern_low_byte(&mut self) {
+        let fine_y = (self.regs.v >> 12) & 0x7;
+        let addr = self.regs.ctrl.background_pattern_table_addr()
+            + self.bg.nt_byte as u16 * 16
+            + fine_y;
+        self.bg.pt_lo_byte = self.vram.loadb(addr);
+    }
+
+    fn fetch_pattern_high_byte(&mut self) {
+        let fine_y = (self.regs.v >> 12) & 0x7;
+        let addr = self.regs.ctrl.background_pattern_table_addr()
+            + self.bg.nt_byte as u16 * 16
+            + fine_y
+            + 8;
+        self.bg.pt_hi_byte = self.vram.loadb(addr);
+    }
+
+    // Loads the low byte of the just-fetched tile into the low bits of the
+    // 16-bit shift registers; the high byte, already shifted in from the
+    // previous tile, occupies the top. Also latches the 2-bit palette
+    // selection for this tile out of the attribute byte.
+    fn reload_background_shift_registers(&mut self) {
+        self.bg.pattern_lo = (self.bg.pattern_lo & 0xff00) | self.bg.pt_lo_byte as u16;
+        self.bg.pattern_hi = (self.bg.pattern_hi & 0xff00) | self.bg.pt_hi_byte as u16;
+
+        let v = self.regs.v;
+        let shift = ((v >> 4) & 0x04) | (v & 0x02);
+        let palette_bits = (self.bg.at_byte >> shift) & 0x3;
+        self.bg.attr_latch_lo = (palette_bits & 0x1) != 0;
+        self.bg.attr_latch_hi = (palette_bits & 0x2) != 0;
+    }
+
+    fn shift_background_registers(&mut self) {
+        self.bg.pattern_lo <<= 1;
+        self.bg.pattern_hi <<= 1;
+        self.bg.attr_lo = (self.bg.attr_lo << 1) | (self.bg.attr_latch_lo as u8);
+        self.bg.attr_hi = (self.bg.attr_hi << 1) | (self.bg.attr_latch_hi as u8);
+    }
+
+    fn output_pixel(&mut self) {
+        let x = (self.cycle - 1) as u8;
+        let y = self.scanline as u8;
+
+        let fine_x = self.regs.x;
+        let bit = 15 - fine_x as u16;
+        let bg_pattern = ((self.bg.pattern_lo >> bit) & 0x1)
+            | (((self.bg.pattern_hi >> bit) & 0x1) << 1);
+        let attr_bit = 7 - fine_x;
+        let bg_palette = ((self.bg.attr_lo >> attr_bit) & 0x1)
+            | (((self.bg.attr_hi >> attr_bit) & 0x1) << 1);
+        let bg_shown = self.regs.mask.show_background()
+            && (x >= 8 || self.regs.mask.show_background_on_left());
+        let bg_opaque = bg_pattern != 0 && bg_shown;
+
+        let (sprite_pattern, sprite_palette, sprite_behind_bg, sprite_is_zero) =
+            self.find_sprite_pixel(x);
+        let sprites_shown = self.regs.mask.show_sprites()
+            && (x >= 8 || self.regs.mask.show_sprites_on_left());
+        let sprite_opaque = sprite_pattern != 0 && sprites_shown;
+
+        if sprite_is_zero && bg_opaque && sprite_opaque && x != 255 && y != 255 {
+            self.regs.status.set_sprite_zero_hit(true);
+        }
+
+        let color = if sprite_opaque && (!bg_opaque || !sprite_behind_bg) {
+            self.vram.loadb(0x3f10 | (sprite_palette as u16 << 2) | sprite_pattern as u16)
+        } else if bg_opaque {
+            self.vram.loadb(0x3f00 | (bg_palette as u16 << 2) | bg_pattern as u16)
+        } else {
+            self.vram.loadb(0x3f00)
+        };
+
+        self.screen.put(x, y, color);
+    }
+
+    // Returns (pattern, palette, behind_background, is_sprite_zero) for the
+    // highest-priority (lowest OAM index) sprite with a non-transparent pixel
+    // at screen column `x`, or a transparent/non-zero result if none has one.
+    fn find_sprite_pixel(&self, x: u8) -> (u8, u8, bool, bool) {
+        let mut i = 0;
+        while i < self.sprites.count {
+            let slot = &self.sprites.slots[i];
+            let column = x as i32 - slot.x as i32;
+            if column >= 0 && column < 8 {
+                let bit = 7 - column as u8;
+                let pattern = ((slot.pattern_lo >> bit) & 0x1)
+                    | (((slot.pattern_hi >> bit) & 0x1) << 1);
+                if pattern != 0 {
+                    let palette = slot.attr & 0x3;
+                    let behind_bg = (slot.attr & 0x20) != 0;
+                    return (pattern, palette, behind_bg, slot.is_sprite_zero);
+                }
+            }
+            i += 1;
+        }
+        (0, 0, false, false)
+    }
+
+    // Scans primary OAM for up to eight sprites covering `target_line`,
+    // copies them into secondary OAM, and fetches their pattern bytes ready
+    // for next scanline's compositing. Sets the sprite-overflow flag if a
+    // ninth sprite would have matched.
+    fn evaluate_and_fetch_sprites(&mut self, target_line: u16) {
+        let height: u16 = match self.regs.ctrl.sprite_size() {
+            SpriteSize8x8 => 8,
+            SpriteSize8x16 => 16,
+        };
+
+        self.sprites.count = 0;
+        let mut i: u16 = 0;
+        while i < 64 {
+            let y = self.oam.loadb(i * 4) as u16;
+            let row = target_line as i32 - y as i32;
+            if row >= 0 && (row as u16) < height {
+                if self.sprites.count < 8 {
+                    let slot_index = self.sprites.count as u16;
+                    let tile = self.oam.loadb(i * 4 + 1);
+                    let attr = self.oam.loadb(i * 4 + 2);
+                    let x = self.oam.loadb(i * 4 + 3);
+                    let secondary_base = slot_index * 4;
+                    self.sprites.secondary_oam[secondary_base] = y as u8;
+                    self.sprites.secondary_oam[secondary_base + 1] = tile;
+                    self.sprites.secondary_oam[secondary_base + 2] = attr;
+                    self.sprites.secondary_oam[secondary_base + 3] = x;
+                    self.fetch_sprite_pattern(slot_index, tile, attr, x, row as u16, height, i == 0);
+                    self.sprites.count += 1;
+                } else {
+                    self.regs.status.set_sprite_overflow(true);
+                }
+            }
+            i += 1;
+        }
+    }
+
+    fn fetch_sprite_pattern(&mut self, slot_index: u16, tile: u8, attr: u8, x: u8,
+                             row: u16, height: u16, is_sprite_zero: bool) {
+        let flip_v = (attr & 0x80) != 0;
+        let flip_h = (attr & 0x40) != 0;
+        let row = if flip_v { height - 1 - row } else { row };
+
+        let addr = if height == 8 {
+            self.regs.ctrl.sprite_pattern_table_addr() + tile as u16 * 16 + row
+        } else {
+            let table = (tile as u16 & 0x1) * 0x1000;
+            let mut tile_num = (tile & 0xfe) as u16;
+            let mut fine_row = row;
+            if fine_row >= 8 {
+                tile_num += 1;
+                fine_row -= 8;
+            }
+            table + tile_num * 16 + fine_row
+        };
+
+        let mut pattern_lo = self.vram.loadb(addr);
+        let mut pattern_hi = self.vram.loadb(addr + 8);
+        if flip_h {
+            pattern_lo = reverse_bits(pattern_lo);
+            pattern_hi = reverse_bits(pattern_hi);
+        }
+
+        let slot = &mut self.sprites.slots[slot_index];
+        slot.pattern_lo = pattern_lo;
+        slot.pattern_hi = pattern_hi;
+        slot.attr = attr;
+        slot.x = x;
+        slot.is_sprite_zero = is_sprite_zero;
+    }
+
+    // coarse X increment, wrapping into the next horizontal nametable.
+    fn increment_coarse_x(&mut self) {
+        if (self.regs.v & 0x001f) == 31 {
+            self.regs.v &= !0x001f;
+            self.regs.v ^= 0x0400;
+        } else {
+            self.regs.v += 1;
+        }
+    }
+
+    // fine Y / coarse Y increment, wrapping into the next vertical nametable.
+    fn increment_y(&mut self) {
+        if (self.regs.v & 0x7000) != 0x7000 {
+            self.regs.v += 0x1000;
+        } else {
+            self.regs.v &= !0x7000;
+            let mut coarse_y = (self.regs.v & 0x03e0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.regs.v ^= 0x0800;
+            } else if coarse_y == 31 {
+                coarse_y = 0;
+            } else {
+                coarse_y += 1;
+            }
+            self.regs.v = (self.regs.v & !0x03e0) | (coarse_y << 5);
+        }
+    }
+
+    fn copy_horizontal_bits(&mut self) {
+        self.regs.v = (self.regs.v & !0x041f) | (self.regs.t & 0x041f);
+    }
+
+    fn copy_vertical_bits(&mut self) {
+        self.regs.v = (self.regs.v & !0x7be0) | (self.regs.t & 0x7be0);
+    }
+
+    fn advance_dot(&mut self, rendering: bool) {
+        // Skipped-dot quirk: on odd frames with rendering enabled, dot 339
+        // of the pre-render scanline is the last one -- dot 340 never
+        // happens, so the next frame's dot 0 starts a cycle early.
+        if self.scanline == PRE_RENDER_SCANLINE && self.cycle == 339 && rendering && self.odd_frame {
+            self.cycle = 0;
+            self.scanline = 0;
+            self.odd_frame = false;
+            return;
+        }
+
+        self.cycle += 1;
+        if self.cycle >= CYCLES_PER_SCANLINE {
+            self.cycle = 0;
+            self.scanline += 1;
+            if self.scanline >= SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    // Snapshots every bit of mutable PPU machine state: registers
+    // (including the internal v/t/x/w scroll latches), OAM, the
+    // background/sprite pipelines (shift registers, latches, and the
+    // sprite slots for the upcoming scanline -- without these a load
+    // mid-frame renders a corrupted first tile and drops that scanline's
+    // sprites), the raster position, the NMI/vblank edge-detector state,
+    // the odd-frame flag, and the PPUDATA read buffer. `vram`'s mapper and
+    // `screen` are excluded -- they're cartridge and front-end state, not
+    // PPU state.
+    //
+    // Takes `&mut self` rather than `&self` because OAM is read back out
+    // through `Mem::loadb`, whose receiver is `&mut self` (the same
+    // constraint `vram` lives under everywhere else in this file).
+    fn save_state(&mut self, out: &mut Writer) {
+        out.write_u8(PPU_STATE_VERSION);
+
+        out.write_u8(*self.regs.ctrl);
+        out.write_u8(*self.regs.mask);
+        out.write_u8(*self.regs.status);
+        out.write_u8(self.regs.oam_addr);
+        out.write_le_u16(self.regs.v);
+        out.write_le_u16(self.regs.t);
+        out.write_u8(self.regs.x);
+        out.write_u8(self.regs.w as u8);
+
+        self.vram.save_state(out);
+
+        let mut i: u16 = 0;
+        while i < 256 {
+            out.write_u8(self.oam.loadb(i));
+            i += 1;
+        }
+
+        self.bg.save_state(out);
+        self.sprites.save_state(out);
+
+        out.write_le_u16(self.scanline);
+        out.write_le_u16(self.cycle);
+        out.write_u8(self.odd_frame as u8);
+
+        out.write_u8(self.nmi_line as u8);
+        out.write_u8(self.nmi_pending);
+        out.write_u8(self.ppudata_buffer);
+    }
+
+    fn load_state(&mut self, src: &mut Reader) {
+        let version = src.read_u8();
+        debug_assert(version == PPU_STATE_VERSION, "unsupported Ppu save-state version");
+
+        self.regs.ctrl = PpuCtrl(src.read_u8());
+        self.regs.mask = PpuMask(src.read_u8());
+        self.regs.status = PpuStatus(src.read_u8());
+        self.regs.oam_addr = src.read_u8();
+        self.regs.v = src.read_le_u16();
+        self.regs.t = src.read_le_u16();
+        self.regs.x = src.read_u8();
+        self.regs.w = src.read_u8() != 0;
+
+        self.vram.load_state(src);
+
+        let mut i: u16 = 0;
+        while i < 256 {
+            self.oam.storeb(i, src.read_u8());
+            i += 1;
+        }
+
+        self.bg.load_state(src);
+        self.sprites.load_state(src);
+
+        self.scanline = src.read_le_u16();
+        self.cycle = src.read_le_u16();
+        self.odd_frame = src.read_u8() != 0;
+
+        self.nmi_line = src.read_u8() != 0;
+        self.nmi_pending = src.read_u8();
+        self.ppudata_buffer = src.read_u8();
     }
 }
 
+static PPU_STATE_VERSION: u8 = 2;
+
+#[cfg(test)]
+mod tests {
+    use super::{Ppu, Regs, PpuCtrl, PpuMask, PpuStatus, BgPipeline, SpritePipeline, Screen,
+                CnromMapper, Mapper};
+    use cpu::Mem;
+    use std::io::mem::{MemWriter, MemReader};
+
+    struct NullMem;
+
+    impl NullMem : Mem {
+        fn loadb(&mut self, _addr: u16) -> u8 { 0 }
+        fn storeb(&mut self, _addr: u16, _val: u8) {}
+        fn loadw(&mut self, _addr: u16) -> u16 { 0 }
+        fn storew(&mut self, _addr: u16, _val: u16) {}
+    }
+
+    struct NullScreen;
+
+    impl NullScreen : Screen {
+        fn put(&mut self, _x: u8, _y: u8, _color: u8) {}
+    }
+
+    // Backed by a plain 256-byte array so tests can populate primary OAM
+    // directly, unlike `NullMem` which always reads back zero.
+    struct ArrayMem {
+        data: [u8 * 256],
+    }
+
+    impl ArrayMem : Mem {
+        fn loadb(&mut self, addr: u16) -> u8 { self.data[addr] }
+        fn storeb(&mut self, addr: u16, val: u8) { self.data[addr] = val; }
+        fn loadw(&mut self, addr: u16) -> u16 {
+            self.data[addr] as u16 | (self.data[addr + 1] as u16 << 8)
+        }
+        fn storew(&mut self, _addr: u16, _val: u16) {}
+    }
+
+    fn test_ppu() -> Ppu<NullMem, NullMem, NullScreen> {
+        Ppu {
+            regs: Regs {
+                ctrl: PpuCtrl(0), mask: PpuMask(0), status: PpuStatus(0),
+                oam_addr: 0, v: 0, t: 0, x: 0, w: false,
+            },
+            vram: NullMem, oam: NullMem, screen: NullScreen,
+            bg: BgPipeline::new(), sprites: SpritePipeline::new(),
+            scanline: 0, cycle: 0, odd_frame: false,
+            nmi_line: false, nmi_pending: 0,
+            ppudata_buffer: 0,
+        }
+    }
+
+    fn sprite_test_ppu(oam: ArrayMem) -> Ppu<NullMem, ArrayMem, NullScreen> {
+        Ppu {
+            regs: Regs {
+                ctrl: PpuCtrl(0), mask: PpuMask(0), status: PpuStatus(0),
+                oam_addr: 0, v: 0, t: 0, x: 0, w: false,
+            },
+            vram: NullMem, oam: oam, screen: NullScreen,
+            bg: BgPipeline::new(), sprites: SpritePipeline::new(),
+            scanline: 0, cycle: 0, odd_frame: false,
+            nmi_line: false, nmi_pending: 0,
+            ppudata_buffer: 0,
+        }
+    }
+
+    // Regression test for secondary OAM: evaluation must copy the matching
+    // sprite's four OAM bytes into `secondary_oam`, not just fetch pattern
+    // bytes straight from primary OAM into `slots`.
+    #[test]
+    fn evaluate_and_fetch_sprites_populates_secondary_oam() {
+        let mut oam = ArrayMem { data: [0u8 * 256] };
+        // Sprite 0: y=10, tile=5, attr=0x01, x=20 -- covers scanlines 10-17.
+        oam.data[0] = 10;
+        oam.data[1] = 5;
+        oam.data[2] = 0x01;
+        oam.data[3] = 20;
+        let mut ppu = sprite_test_ppu(oam);
+
+        ppu.evaluate_and_fetch_sprites(12);
+
+        assert_eq!(ppu.sprites.count, 1);
+        assert_eq!(ppu.sprites.secondary_oam[0], 10);
+        assert_eq!(ppu.sprites.secondary_oam[1], 5);
+        assert_eq!(ppu.sprites.secondary_oam[2], 0x01);
+        assert_eq!(ppu.sprites.secondary_oam[3], 20);
+        assert_eq!(ppu.sprites.slots[0].x, 20);
+        assert_eq!(ppu.sprites.slots[0].attr, 0x01);
+        assert!(ppu.sprites.slots[0].is_sprite_zero);
+    }
+
+    // Regression test: a CHR-RAM cart ships with an empty CHR array in the
+    // ROM image, so the mapper must allocate its own backing buffer rather
+    // than indexing into that zero-length vector (which panics on the very
+    // first access).
+    #[test]
+    fn cnrom_mapper_allocates_chr_ram_buffer_when_rom_has_no_chr() {
+        let mut mapper = CnromMapper::from_chr(~[]);
+
+        mapper.chr_storeb(0x0000, 0x42);
+        assert_eq!(mapper.chr_loadb(0x0000), 0x42);
+    }
+
+    // Regression test for the vbl-NMI timing behavior: the /NMI line is a
+    // logical AND of the vblank flag and PPUCTRL's enable bit, so toggling
+    // the enable bit back on while vblank is still set must re-trigger a
+    // rising edge -- and hence queue another NMI -- even though the vblank
+    // flag itself never changed.
+    #[test]
+    fn toggling_ppuctrl_nmi_enable_during_vblank_refires_nmi() {
+        let mut ppu = test_ppu();
+        ppu.regs.status.set_in_vblank(true);
+
+        ppu.update_ppuctrl(0x80);
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+
+        ppu.update_ppuctrl(0x00);
+        assert!(!ppu.take_nmi());
+
+        ppu.update_ppuctrl(0x80);
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn leaving_vblank_clears_the_nmi_line_without_queuing_one() {
+        let mut ppu = test_ppu();
+        ppu.regs.status.set_in_vblank(true);
+        ppu.update_ppuctrl(0x80);
+        assert!(ppu.take_nmi());
+
+        ppu.leave_vblank();
+        assert!(!ppu.take_nmi());
+    }
+
+    // Regression test for save/load: the background and sprite pipelines
+    // are genuinely mutable per-dot state, not just the registers/OAM/raster
+    // position, so a round trip must restore them too.
+    #[test]
+    fn save_state_round_trips_bg_and_sprite_pipeline_state() {
+        let mut ppu = test_ppu();
+        ppu.bg.pattern_lo = 0xbeef;
+        ppu.bg.attr_lo = 0x5;
+        ppu.sprites.count = 3;
+        ppu.sprites.slots[0].x = 42;
+        ppu.sprites.secondary_oam[0] = 7;
+        ppu.scanline = 100;
+        ppu.cycle = 200;
+
+        let mut writer = MemWriter::new();
+        ppu.save_state(&mut writer);
+
+        let mut loaded = test_ppu();
+        let mut reader = MemReader::new(writer.get_ref().to_owned());
+        loaded.load_state(&mut reader);
+
+        assert_eq!(loaded.bg.pattern_lo, 0xbeef);
+        assert_eq!(loaded.bg.attr_lo, 0x5);
+        assert_eq!(loaded.sprites.count, 3);
+        assert_eq!(loaded.sprites.slots[0].x, 42);
+        assert_eq!(loaded.sprites.secondary_oam[0], 7);
+        assert_eq!(loaded.scanline, 100);
+        assert_eq!(loaded.cycle, 200);
+    }
+}